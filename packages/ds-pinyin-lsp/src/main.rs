@@ -1,11 +1,12 @@
 use dashmap::DashMap;
-use ds_pinyin_lsp::types::Setting;
+use ds_pinyin_lsp::types::{Setting, Suggest};
 use ds_pinyin_lsp::utils::{
     get_pinyin, get_pre_line, query_dict, query_words, suggest_to_completion_item,
 };
 use lsp_document::{apply_change, IndexedText, TextAdapter};
 use rusqlite::Connection;
 use serde_json::Value;
+use std::collections::HashSet;
 use tokio::sync::Mutex;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
@@ -15,8 +16,59 @@ use tower_lsp::{Client, LanguageServer, LspService, Server};
 struct Backend {
     client: Client,
     setting: Mutex<Option<Setting>>,
-    conn: Mutex<Option<Connection>>,
+    sources: Mutex<Vec<DictSource>>,
     documents: DashMap<String, IndexedText<String>>,
+    position_encoding: Mutex<PositionEncodingKind>,
+    english_mode: Mutex<bool>,
+    max_completion_items: Mutex<usize>,
+    completion_on: Mutex<bool>,
+}
+
+/// A single opened dictionary, its declared query capabilities, and where it
+/// sits in the lookup order. Lower `priority` wins, so a user dictionary layered
+/// with `priority = 0` surfaces above the bundled one.
+#[derive(Debug)]
+struct DictSource {
+    conn: Connection,
+    features: DictFeatures,
+    priority: i64,
+}
+
+/// Which of the three query stages a source opts into, derived from its
+/// `features` list. The stages mirror the baseline cascade: `words-match`
+/// (whole-pinyin phrases), `words-search` (prefix phrases), and `dict-search`
+/// (single characters).
+#[derive(Debug, Clone, Copy)]
+struct DictFeatures {
+    words_match: bool,
+    words_search: bool,
+    dict_search: bool,
+}
+
+impl DictFeatures {
+    /// Parse a `features` list. The granular stage names
+    /// (`words-match`/`words-search`/`dict-search`) toggle individual stages;
+    /// the coarse `words`/`dict` aliases cover both words stages and the dict
+    /// stage respectively. A source with no declared features participates in
+    /// every stage, matching the single-db behaviour.
+    fn from_value(value: Option<&Value>) -> Self {
+        match value.and_then(Value::as_array) {
+            Some(features) => {
+                let names: Vec<&str> = features.iter().filter_map(Value::as_str).collect();
+                let has = |name: &str| names.iter().any(|f| *f == name);
+                DictFeatures {
+                    words_match: has("words") || has("words-match"),
+                    words_search: has("words") || has("words-search"),
+                    dict_search: has("dict") || has("dict-search"),
+                }
+            }
+            None => DictFeatures {
+                words_match: true,
+                words_search: true,
+                dict_search: true,
+            },
+        }
+    }
 }
 
 #[tower_lsp::async_trait]
@@ -24,9 +76,22 @@ impl LanguageServer for Backend {
     async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
         self.init(&params.initialization_options).await;
 
+        // Negotiate the position encoding: prefer utf-8 when the client offers
+        // it so our column math lines up with byte offsets, otherwise fall back
+        // to the LSP default of utf-16.
+        let encoding = params
+            .capabilities
+            .general
+            .and_then(|general| general.position_encodings)
+            .filter(|encodings| encodings.contains(&PositionEncodingKind::UTF8))
+            .map(|_| PositionEncodingKind::UTF8)
+            .unwrap_or(PositionEncodingKind::UTF16);
+        *self.position_encoding.lock().await = encoding.clone();
+
         Ok(InitializeResult {
             server_info: None,
             capabilities: ServerCapabilities {
+                position_encoding: Some(encoding),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
                     TextDocumentSyncKind::INCREMENTAL,
                 )),
@@ -36,15 +101,46 @@ impl LanguageServer for Backend {
                     work_done_progress_options: Default::default(),
                     all_commit_characters: None,
                 }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        "ds-pinyin.reloadDictionary".to_string(),
+                        "ds-pinyin.switchDictionary".to_string(),
+                        "ds-pinyin.toggleEnglishMode".to_string(),
+                    ],
+                    work_done_progress_options: Default::default(),
+                }),
                 ..ServerCapabilities::default()
             },
         })
     }
 
+    async fn initialized(&self, _: InitializedParams) {
+        // Ask clients that require it to actually push configuration changes.
+        let registration = Registration {
+            id: "ds-pinyin-did-change-configuration".to_string(),
+            method: "workspace/didChangeConfiguration".to_string(),
+            register_options: None,
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    &format!("ds-pinyin-lsp failed to register for configuration changes: {}", err),
+                )
+                .await;
+        }
+    }
+
     async fn shutdown(&self) -> Result<()> {
         Ok(())
     }
 
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        // Re-run the same validation/error-reporting path as `initialize` so a
+        // new db-path (or dictionaries list) takes effect without a restart.
+        self.init(&Some(params.settings)).await;
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         self.documents.insert(
             params.text_document.uri.to_string(),
@@ -80,10 +176,17 @@ impl LanguageServer for Backend {
     }
 
     async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        // In English mode, or when completion is switched off, we suppress
+        // candidates so latin text can be typed without pinyin noise.
+        if *self.english_mode.lock().await || !*self.completion_on.lock().await {
+            return Ok(Some(CompletionResponse::Array(vec![])));
+        }
+
         let position = params.text_document_position.position;
         let uri = params.text_document_position.text_document.uri.to_string();
         let document = self.documents.get(&uri);
-        let pre_line = get_pre_line(&document, &position).unwrap_or("");
+        let encoding = self.position_encoding.lock().await.clone();
+        let pre_line = get_pre_line(&document, &position, &encoding).unwrap_or("");
 
         if pre_line.is_empty() {
             return Ok(Some(CompletionResponse::Array(vec![])));
@@ -95,115 +198,496 @@ impl LanguageServer for Backend {
             return Ok(Some(CompletionResponse::Array(vec![])));
         }
 
+        // Express the replacement start in the negotiated units. The pinyin is
+        // ASCII so utf-8 and utf-16 agree today, but compute it explicitly so a
+        // non-ASCII trigger would still line up with the client's offsets.
+        let pinyin_len = encoding_len(&pinyin, &encoding);
         let range = Range::new(
             Position {
                 line: position.line,
-                character: position.character - pinyin.len() as u32,
+                character: position.character - pinyin_len,
             },
             position,
         );
 
-        if let Some(ref conn) = *self.conn.lock().await {
-            // words match
-            if let Ok(suggest) = query_words(conn, &pinyin, true) {
-                if suggest.len() > 0 {
-                    return Ok(Some(CompletionResponse::List(CompletionList {
-                        is_incomplete: true,
-                        items: suggest_to_completion_item(suggest, range),
-                    })));
+        // Walk the configured sources in priority order, merging their hits. A
+        // suggestion from a higher-priority source wins, so phrases from a
+        // layered user dictionary surface above the bundled one.
+        let mut merged: Vec<Suggest> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+
+        for source in self.sources.lock().await.iter() {
+            let conn = &source.conn;
+
+            // Per source, keep the baseline cascade: stop at the first stage
+            // that yields anything (words-match → words-search → dict-search)
+            // so a source doesn't flood fuzzy hits behind its exact matches.
+            let mut stage: Vec<Suggest> = Vec::new();
+            if source.features.words_match {
+                if let Ok(suggest) = query_words(conn, &pinyin, true) {
+                    stage = suggest;
                 }
             }
-
-            // words search
-            if let Ok(suggest) = query_words(conn, &pinyin, false) {
-                if suggest.len() > 0 {
-                    return Ok(Some(CompletionResponse::List(CompletionList {
-                        is_incomplete: true,
-                        items: suggest_to_completion_item(suggest, range),
-                    })));
+            if stage.is_empty() && source.features.words_search {
+                if let Ok(suggest) = query_words(conn, &pinyin, false) {
+                    stage = suggest;
                 }
             }
-
-            // dict search
-            if let Ok(suggest) = query_dict(conn, &pinyin) {
-                if suggest.len() > 0 {
-                    return Ok(Some(CompletionResponse::List(CompletionList {
-                        is_incomplete: true,
-                        items: suggest_to_completion_item(suggest, range),
-                    })));
+            if stage.is_empty() && source.features.dict_search {
+                if let Ok(suggest) = query_dict(conn, &pinyin) {
+                    stage = suggest;
                 }
             }
-        };
+
+            // Merge between sources: higher-priority sources are visited first,
+            // so their phrases win on the dedup.
+            push_unique(&mut merged, &mut seen, stage);
+        }
+
+        if !merged.is_empty() {
+            // cap the returned list length; 0 means unlimited
+            let max = *self.max_completion_items.lock().await;
+            if max > 0 {
+                merged.truncate(max);
+            }
+            return Ok(Some(CompletionResponse::List(CompletionList {
+                is_incomplete: true,
+                items: suggest_to_completion_item(merged, range),
+            })));
+        }
 
         Ok(Some(CompletionResponse::Array(vec![])))
     }
-}
 
-impl Backend {
-    async fn init(&self, initialization_options: &Option<Value>) {
-        if let Some(params) = initialization_options {
-            let mut setting = self.setting.lock().await;
+    async fn completion_resolve(&self, mut item: CompletionItem) -> Result<CompletionItem> {
+        // `suggest_to_completion_item` stashes the candidate hanzi/pinyin in
+        // `data`; without it there is nothing to resolve against.
+        let data = match item.data.clone() {
+            Some(data) => data,
+            None => return Ok(item),
+        };
 
-            let db_path = &Value::String(String::new());
+        // Guard against resolve storms: once we have filled an item in we leave
+        // a sentinel behind so repeated resolve requests for the same candidate
+        // short-circuit before touching the database again.
+        if data
+            .get("resolved")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+        {
+            return Ok(item);
+        }
 
-            let db_path = params.get("db-path").unwrap_or(&db_path);
+        let hanzi = data.get("hanzi").and_then(Value::as_str).unwrap_or("");
+        let pinyin = data.get("pinyin").and_then(Value::as_str).unwrap_or("");
 
-            // invalid db_path
-            if !db_path.is_string() {
-                return self
-                    .client
-                    .show_message(MessageType::ERROR, "ds-pinyin-lsp db-path must be string!")
+        if hanzi.is_empty() {
+            return Ok(item);
+        }
+
+        // Resolve against the highest-priority source that can answer; fall
+        // through to the next one when a lookup comes up empty.
+        for source in self.sources.lock().await.iter() {
+            if let Ok(resolved) = resolve_candidate(&source.conn, source.features, hanzi, pinyin) {
+                if resolved.detail.is_some() || resolved.documentation.is_some() {
+                    item.detail = resolved.detail;
+                    item.documentation = resolved.documentation;
+                    break;
+                }
+            }
+        }
+
+        // mark the item resolved so subsequent resolve calls are free
+        let mut data = data;
+        if let Value::Object(ref mut map) = data {
+            map.insert("resolved".to_string(), Value::Bool(true));
+        }
+        item.data = Some(data);
+
+        Ok(item)
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
+        match params.command.as_str() {
+            // Reopen every configured source from its current path, e.g. after
+            // the user regenerated the SQLite file.
+            "ds-pinyin.reloadDictionary" => {
+                let mut sources = self.sources.lock().await;
+                let mut reloaded: Vec<DictSource> = Vec::with_capacity(sources.len());
+                for source in sources.iter() {
+                    let path = path_of(&source.conn);
+                    match Connection::open(&path) {
+                        Ok(conn) => reloaded.push(DictSource {
+                            conn,
+                            features: source.features,
+                            priority: source.priority,
+                        }),
+                        Err(err) => {
+                            self.client
+                                .show_message(
+                                    MessageType::ERROR,
+                                    &format!("Open database error: {}", err),
+                                )
+                                .await;
+                            return Ok(None);
+                        }
+                    }
+                }
+                *sources = reloaded;
+                self.client
+                    .log_message(MessageType::INFO, "ds-pinyin-lsp dictionary reloaded!")
                     .await;
             }
 
-            if let Some(db_path) = db_path.as_str() {
-                // db_path missing
-                if db_path.is_empty() {
-                    return self
-                        .client
+            // Swap to a single dictionary at the supplied path, validated the
+            // same way `init` validates `db-path`.
+            "ds-pinyin.switchDictionary" => {
+                let path = params
+                    .arguments
+                    .first()
+                    .and_then(Value::as_str)
+                    .unwrap_or("");
+
+                if path.is_empty() {
+                    self.client
                         .show_message(
                             MessageType::ERROR,
-                            "ds-pinyin-lsp db-path is missing or empty!",
+                            "ds-pinyin.switchDictionary requires a non-empty path argument!",
                         )
                         .await;
+                    return Ok(None);
                 }
 
-                // cache setting
-                *setting = Some(Setting {
-                    db_path: db_path.to_string(),
-                });
+                match Connection::open(path) {
+                    Ok(conn) => {
+                        *self.setting.lock().await = Some(Setting {
+                            db_path: path.to_string(),
+                        });
+                        *self.sources.lock().await = vec![DictSource {
+                            conn,
+                            features: DictFeatures {
+                                words_match: true,
+                                words_search: true,
+                                dict_search: true,
+                            },
+                            priority: DEFAULT_PRIORITY,
+                        }];
+                        self.client
+                            .log_message(
+                                MessageType::INFO,
+                                &format!("ds-pinyin-lsp switched dictionary to {}", path),
+                            )
+                            .await;
+                    }
+                    Err(err) => {
+                        self.client
+                            .show_message(
+                                MessageType::ERROR,
+                                &format!("Open database error: {}", err),
+                            )
+                            .await;
+                    }
+                }
+            }
 
-                // open db connection
-                let conn = Connection::open(db_path);
-                if let Ok(conn) = conn {
-                    let mut mutex = self.conn.lock().await;
-                    *mutex = Some(conn);
-                    return self
-                        .client
-                        .log_message(
-                            MessageType::INFO,
-                            "ds-pinyin-lsp db connection initialized!",
-                        )
-                        .await;
-                } else if let Err(err) = conn {
+            // Flip English mode; `completion` honors the flag.
+            "ds-pinyin.toggleEnglishMode" => {
+                let mut english_mode = self.english_mode.lock().await;
+                *english_mode = !*english_mode;
+                self.client
+                    .log_message(
+                        MessageType::INFO,
+                        &format!("ds-pinyin-lsp english mode: {}", *english_mode),
+                    )
+                    .await;
+            }
+
+            other => {
+                self.client
+                    .show_message(
+                        MessageType::WARNING,
+                        &format!("ds-pinyin-lsp unknown command: {}", other),
+                    )
+                    .await;
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Append suggestions whose hanzi hasn't been seen yet, preserving the order in
+/// which the (priority-ordered) sources produced them.
+fn push_unique(merged: &mut Vec<Suggest>, seen: &mut HashSet<String>, suggest: Vec<Suggest>) {
+    for item in suggest {
+        if seen.insert(item.hanzi.clone()) {
+            merged.push(item);
+        }
+    }
+}
+
+/// Width of `text` expressed in the negotiated position-encoding units.
+fn encoding_len(text: &str, encoding: &PositionEncodingKind) -> u32 {
+    if *encoding == PositionEncodingKind::UTF8 {
+        text.len() as u32
+    } else {
+        text.chars().map(|c| c.len_utf16() as u32).sum()
+    }
+}
+
+/// Supplementary information fetched lazily in `completion_resolve`.
+struct ResolvedCandidate {
+    detail: Option<String>,
+    documentation: Option<Documentation>,
+}
+
+/// Run the expensive secondary lookups for a single candidate: its word
+/// frequency, any alternate readings, and a handful of example words that
+/// begin with the candidate hanzi. Kept out of `completion` so the initial
+/// list stays cheap.
+fn resolve_candidate(
+    conn: &Connection,
+    features: DictFeatures,
+    hanzi: &str,
+    pinyin: &str,
+) -> rusqlite::Result<ResolvedCandidate> {
+    // Only touch the tables this source actually serves; a source that opted
+    // out of `dict`/`words` must not be queried on resolve either.
+    let use_dict = features.dict_search;
+    let use_words = features.words_match || features.words_search;
+
+    // A source that can answer neither stage contributes nothing, so the
+    // resolve loop falls through to the next source.
+    if !use_dict && !use_words {
+        return Ok(ResolvedCandidate {
+            detail: None,
+            documentation: None,
+        });
+    }
+
+    // word frequency / priority for the selected reading (dict stage)
+    let priority: Option<i64> = if use_dict {
+        conn.query_row(
+            "SELECT priority FROM dict WHERE hanzi = ?1 AND pinyin = ?2 ORDER BY priority DESC LIMIT 1",
+            [hanzi, pinyin],
+            |row| row.get(0),
+        )
+        .ok()
+    } else {
+        None
+    };
+
+    // alternate pinyin readings for the same hanzi (dict stage)
+    let readings: Vec<String> = if use_dict {
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT pinyin FROM dict WHERE hanzi = ?1 AND pinyin != ?2 ORDER BY priority DESC LIMIT 5",
+        )?;
+        let readings = stmt
+            .query_map([hanzi, pinyin], |row| row.get::<_, String>(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        readings
+    } else {
+        Vec::new()
+    };
+
+    // example multi-character words starting with this candidate (words stage)
+    let examples: Vec<String> = if use_words {
+        let mut stmt = conn.prepare(
+            "SELECT hanzi FROM words WHERE hanzi LIKE ?1 || '%' AND length(hanzi) > 1 ORDER BY priority DESC LIMIT 5",
+        )?;
+        let examples = stmt
+            .query_map([hanzi], |row| row.get::<_, String>(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        examples
+    } else {
+        Vec::new()
+    };
+
+    // Nothing found here — let the resolve loop fall through to the next,
+    // lower-priority source that may actually hold this candidate.
+    if priority.is_none() && readings.is_empty() && examples.is_empty() {
+        return Ok(ResolvedCandidate {
+            detail: None,
+            documentation: None,
+        });
+    }
+
+    let detail = match priority {
+        Some(priority) => Some(format!("{} ({})  freq {}", hanzi, pinyin, priority)),
+        None => Some(format!("{} ({})", hanzi, pinyin)),
+    };
+
+    let mut doc = String::new();
+    if !readings.is_empty() {
+        doc.push_str(&format!("**Readings**: {}\n\n", readings.join(", ")));
+    }
+    if !examples.is_empty() {
+        doc.push_str("**Examples**:\n");
+        for word in &examples {
+            doc.push_str(&format!("- {}\n", word));
+        }
+    }
+
+    let documentation = if doc.is_empty() {
+        None
+    } else {
+        Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: doc,
+        }))
+    };
+
+    Ok(ResolvedCandidate {
+        detail,
+        documentation,
+    })
+}
+
+impl Backend {
+    async fn init(&self, initialization_options: &Option<Value>) {
+        let params = match initialization_options {
+            Some(params) => params,
+            None => {
+                return self
+                    .client
+                    .show_message(
+                        MessageType::ERROR,
+                        "ds-pinyin-lsp initialization_options is missing, it must include db-path setting!",
+                    )
+                    .await;
+            }
+        };
+
+        // Apply tunables up front so a config push carrying only these still
+        // takes effect even when it names no dictionary.
+        if let Some(max) = params.get("max-completion-items").and_then(Value::as_u64) {
+            *self.max_completion_items.lock().await = max as usize;
+        }
+        if let Some(on) = params.get("completion-on").and_then(Value::as_bool) {
+            *self.completion_on.lock().await = on;
+        }
+
+        let mut setting = self.setting.lock().await;
+
+        // Prefer the layered `dictionaries` list; fall back to the single
+        // `db-path` for backwards compatibility. A config update that names
+        // neither leaves the currently loaded sources in place.
+        let mut sources: Vec<DictSource> = Vec::new();
+
+        if params.get("dictionaries").is_none()
+            && params.get("db-path").is_none()
+            && !self.sources.lock().await.is_empty()
+        {
+            return;
+        }
+
+        if let Some(dictionaries) = params.get("dictionaries").and_then(Value::as_array) {
+            for dictionary in dictionaries {
+                let path = match dictionary.get("path").and_then(Value::as_str) {
+                    Some(path) if !path.is_empty() => path,
+                    _ => {
+                        return self
+                            .client
+                            .show_message(
+                                MessageType::ERROR,
+                                "ds-pinyin-lsp dictionary entries must include a non-empty path!",
+                            )
+                            .await;
+                    }
+                };
+
+                let features = DictFeatures::from_value(dictionary.get("features"));
+                let priority = dictionary
+                    .get("priority")
+                    .and_then(Value::as_i64)
+                    .unwrap_or(DEFAULT_PRIORITY);
+
+                match Connection::open(path) {
+                    Ok(conn) => sources.push(DictSource {
+                        conn,
+                        features,
+                        priority,
+                    }),
+                    Err(err) => {
+                        return self
+                            .client
+                            .show_message(
+                                MessageType::ERROR,
+                                &format!("Open database error: {}", err),
+                            )
+                            .await;
+                    }
+                }
+            }
+        } else {
+            let db_path = params
+                .get("db-path")
+                .and_then(Value::as_str)
+                .unwrap_or("");
+
+            // db_path missing
+            if db_path.is_empty() {
+                return self
+                    .client
+                    .show_message(
+                        MessageType::ERROR,
+                        "ds-pinyin-lsp db-path is missing or empty!",
+                    )
+                    .await;
+            }
+
+            match Connection::open(db_path) {
+                Ok(conn) => sources.push(DictSource {
+                    conn,
+                    features: DictFeatures {
+                        words_match: true,
+                        words_search: true,
+                        dict_search: true,
+                    },
+                    priority: DEFAULT_PRIORITY,
+                }),
+                Err(err) => {
                     return self
                         .client
                         .show_message(MessageType::ERROR, &format!("Open database error: {}", err))
                         .await;
                 }
             }
-        } else {
-            return self
-                .client
-                .show_message(
-                    MessageType::ERROR,
-                    "ds-pinyin-lsp initialization_options is missing, it must include db-path setting!",
-                )
-                .await;
         }
+
+        // lower priority value wins, so user dictionaries stack above the bundled one
+        sources.sort_by_key(|source| source.priority);
+
+        // cache setting, keyed off the highest-priority source
+        if let Some(primary) = sources.first() {
+            *setting = Some(Setting {
+                db_path: path_of(&primary.conn),
+            });
+        }
+
+        *self.sources.lock().await = sources;
+
+        self.client
+            .log_message(
+                MessageType::INFO,
+                "ds-pinyin-lsp db connection initialized!",
+            )
+            .await;
     }
 }
 
+/// Default source priority: higher than any user-supplied `priority = 0`, so
+/// an unannotated source sits below an explicitly prioritized one.
+const DEFAULT_PRIORITY: i64 = 100;
+
+/// Best-effort recovery of the file a connection was opened from, used to keep
+/// `Setting::db_path` pointing at the primary dictionary.
+fn path_of(conn: &Connection) -> String {
+    conn.path().map(|p| p.to_string()).unwrap_or_default()
+}
+
 #[tokio::main]
 async fn main() {
     let stdin = tokio::io::stdin();
@@ -212,8 +696,12 @@ async fn main() {
     let (service, socket) = LspService::build(|client| Backend {
         client,
         setting: Mutex::new(None),
-        conn: Mutex::new(None),
+        sources: Mutex::new(Vec::new()),
         documents: DashMap::new(),
+        position_encoding: Mutex::new(PositionEncodingKind::UTF16),
+        english_mode: Mutex::new(false),
+        max_completion_items: Mutex::new(0),
+        completion_on: Mutex::new(true),
     })
     .finish();
 
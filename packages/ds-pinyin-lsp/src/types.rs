@@ -0,0 +1,13 @@
+/// Server configuration parsed from the client's `initialization_options`.
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub db_path: String,
+}
+
+/// A single completion candidate read from one of the dictionary tables.
+#[derive(Debug, Clone)]
+pub struct Suggest {
+    pub hanzi: String,
+    pub pinyin: String,
+    pub priority: u64,
+}
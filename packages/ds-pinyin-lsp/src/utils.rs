@@ -0,0 +1,186 @@
+use crate::types::Suggest;
+use dashmap::mapref::one::Ref;
+use lsp_document::{IndexedText, TextMap};
+use rusqlite::{Connection, Row};
+use serde_json::json;
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionTextEdit, Position, PositionEncodingKind, Range,
+    TextEdit,
+};
+
+/// Recover the pinyin the user is currently typing from the text before the
+/// cursor: the trailing run of ascii letters on the line.
+pub fn get_pinyin(pre_line: &str) -> Option<String> {
+    let pinyin: String = pre_line
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    if pinyin.is_empty() {
+        None
+    } else {
+        Some(pinyin.chars().rev().collect())
+    }
+}
+
+/// Translate a `character` column — expressed in `encoding` units — into a byte
+/// offset inside `line`. UTF-8 columns already count bytes; UTF-16 columns count
+/// code units, so we walk the line accumulating `len_utf16` until we reach it.
+fn character_to_byte(line: &str, character: u32, encoding: &PositionEncodingKind) -> usize {
+    if *encoding == PositionEncodingKind::UTF8 {
+        let target = (character as usize).min(line.len());
+        if target == line.len() {
+            return target;
+        }
+        // Snap down to a char boundary so a column that lands mid-hanzi can't
+        // panic the slice in `pre_line_of`.
+        let mut boundary = 0;
+        for (offset, _) in line.char_indices() {
+            if offset > target {
+                break;
+            }
+            boundary = offset;
+        }
+        return boundary;
+    }
+    let mut units = 0u32;
+    for (offset, ch) in line.char_indices() {
+        if units >= character {
+            return offset;
+        }
+        units += ch.len_utf16() as u32;
+    }
+    line.len()
+}
+
+/// Slice the current line up to the cursor, honoring the negotiated encoding so
+/// the column lands on the right byte even when committed hanzi precede it.
+pub(crate) fn pre_line_of<'a>(
+    text: &'a str,
+    position: &Position,
+    encoding: &PositionEncodingKind,
+) -> Option<&'a str> {
+    let line = text.split('\n').nth(position.line as usize)?;
+    Some(&line[..character_to_byte(line, position.character, encoding)])
+}
+
+/// The text on the current line before the cursor, used to recover the pinyin
+/// being typed. The column is interpreted in the negotiated `encoding` so the
+/// slice matches the client's offsets regardless of earlier multi-byte hanzi.
+pub fn get_pre_line<'a>(
+    document: &'a Option<Ref<'a, String, IndexedText<String>>>,
+    position: &Position,
+    encoding: &PositionEncodingKind,
+) -> Option<&'a str> {
+    let document = document.as_ref()?;
+    pre_line_of(document.text(), position, encoding)
+}
+
+fn row_to_suggest(row: &Row) -> rusqlite::Result<Suggest> {
+    Ok(Suggest {
+        hanzi: row.get(0)?,
+        pinyin: row.get(1)?,
+        priority: row.get::<_, i64>(2)? as u64,
+    })
+}
+
+/// Look up phrases in the `words` table. `exact` selects the words-match stage
+/// (whole-pinyin equality); otherwise it is the looser words-search prefix scan.
+pub fn query_words(conn: &Connection, pinyin: &str, exact: bool) -> rusqlite::Result<Vec<Suggest>> {
+    let sql = if exact {
+        "SELECT hanzi, pinyin, priority FROM words WHERE pinyin = ?1 ORDER BY priority DESC LIMIT 50"
+    } else {
+        "SELECT hanzi, pinyin, priority FROM words WHERE pinyin LIKE ?1 || '%' ORDER BY priority DESC LIMIT 50"
+    };
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map([pinyin], row_to_suggest)?;
+    // Skip the odd malformed row rather than discarding the whole stage.
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+/// The dict-search stage: single-character readings from the `dict` table.
+pub fn query_dict(conn: &Connection, pinyin: &str) -> rusqlite::Result<Vec<Suggest>> {
+    let mut stmt = conn.prepare(
+        "SELECT hanzi, pinyin, priority FROM dict WHERE pinyin = ?1 ORDER BY priority DESC LIMIT 50",
+    )?;
+    let rows = stmt.query_map([pinyin], row_to_suggest)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+/// Turn the merged suggestions into completion items anchored at `range`. The
+/// list stays cheap here — no per-item DB hits; the candidate hanzi/pinyin is
+/// stashed in `data` so `completion_resolve` can fill supplementary detail
+/// lazily.
+pub fn suggest_to_completion_item(suggests: Vec<Suggest>, range: Range) -> Vec<CompletionItem> {
+    suggests
+        .into_iter()
+        .enumerate()
+        .map(|(index, suggest)| CompletionItem {
+            label: suggest.hanzi.clone(),
+            kind: Some(CompletionItemKind::TEXT),
+            filter_text: Some(suggest.pinyin.clone()),
+            sort_text: Some(format!("{:08}", index)),
+            data: Some(json!({ "hanzi": suggest.hanzi, "pinyin": suggest.pinyin })),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                range,
+                new_text: suggest.hanzi,
+            })),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two committed hanzi followed by the ascii pinyin still being typed.
+    const LINE: &str = "你好ni";
+
+    #[test]
+    fn pre_line_utf16_columns() {
+        // Each BMP hanzi is one UTF-16 code unit, so the cursor after "ni" is
+        // at column 4.
+        let pos = Position {
+            line: 0,
+            character: 4,
+        };
+        let pre = pre_line_of(LINE, &pos, &PositionEncodingKind::UTF16).unwrap();
+        assert_eq!(pre, "你好ni");
+        assert_eq!(get_pinyin(pre).as_deref(), Some("ni"));
+    }
+
+    #[test]
+    fn pre_line_utf8_columns() {
+        // The same cursor is at byte column 8 (3 + 3 + 1 + 1) under UTF-8.
+        let pos = Position {
+            line: 0,
+            character: 8,
+        };
+        let pre = pre_line_of(LINE, &pos, &PositionEncodingKind::UTF8).unwrap();
+        assert_eq!(pre, "你好ni");
+        assert_eq!(get_pinyin(pre).as_deref(), Some("ni"));
+    }
+
+    #[test]
+    fn cursor_between_hanzi_and_pinyin() {
+        // Right after the hanzi, before any pinyin, both encodings agree on the
+        // slice even though the columns differ.
+        let utf16 = Position {
+            line: 0,
+            character: 2,
+        };
+        assert_eq!(
+            pre_line_of(LINE, &utf16, &PositionEncodingKind::UTF16).unwrap(),
+            "你好"
+        );
+        let utf8 = Position {
+            line: 0,
+            character: 6,
+        };
+        assert_eq!(
+            pre_line_of(LINE, &utf8, &PositionEncodingKind::UTF8).unwrap(),
+            "你好"
+        );
+    }
+}